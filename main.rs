@@ -1,31 +1,151 @@
-use clap::{Parser, Subcommand, ValueHint};
+use clap::{Parser, Subcommand, ValueEnum, ValueHint};
+use std::ffi::OsString;
 use std::fs;
+use std::io::{BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver};
 use std::thread;
-use std::time::Duration;
-use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::os::windows::process::CommandExt;
 use std::sync::{Arc, Mutex};
 use tracing::{info, error, warn, debug};
+use serde::{Serialize, Deserialize};
+use windows_sys::Win32::System::Console::{AllocConsole, GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT};
+use windows_sys::Win32::System::Threading::{
+    ABOVE_NORMAL_PRIORITY_CLASS, BELOW_NORMAL_PRIORITY_CLASS, CREATE_NEW_PROCESS_GROUP,
+    CREATE_NO_WINDOW, HIGH_PRIORITY_CLASS, IDLE_PRIORITY_CLASS, NORMAL_PRIORITY_CLASS,
+    REALTIME_PRIORITY_CLASS,
+};
 use tracing_subscriber::fmt;
 use tracing_subscriber::fmt::time::LocalTime;
 use tracing_subscriber::EnvFilter;
+use winreg::enums::{HKEY_CURRENT_USER, KEY_SET_VALUE};
+use winreg::RegKey;
 use windows_service::{
     service::{
-        ServiceAccess, ServiceControlAccept, ServiceErrorControl, ServiceInfo, ServiceStartType,
-        ServiceStatus, ServiceState, ServiceType,
+        ServiceAccess, ServiceControlAccept, ServiceDependency, ServiceErrorControl,
+        ServiceExitCode, ServiceInfo, ServiceStartType, ServiceStatus, ServiceState, ServiceType,
     },
-    service_control_handler::{self, ServiceControlHandlerResult},
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
     service_dispatcher,
     service_manager::{ServiceManager, ServiceManagerAccess},
 };
 
-static CLI: once_cell::sync::Lazy<Arc<Mutex<Option<Cli>>>> = once_cell::sync::Lazy::new(|| Arc::new(Mutex::new(None)));
+/// A restart is only counted against `--max-restarts` if it happens within this long of the
+/// previous one; once the child has stayed up longer than this, the window resets.
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+/// The backoff delay between restart attempts doubles after each crash, up to this cap.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+/// Maximum size of a single child-output log file before it is rotated.
+const CHILD_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Maximum age of a single child-output log file before it is rotated.
+const CHILD_LOG_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Registry path (relative to HKEY_CURRENT_USER) used for user-mode autostart.
+const RUN_KEY_PATH: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+
+/// How the wrapper should react when the wrapped batch file's process exits on its own.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum RestartPolicy {
+    /// Always restart the child, even if it exited successfully.
+    Always,
+    /// Restart the child only if it exited with a non-zero/unknown status.
+    OnFailure,
+    /// Never restart; the service stops once the child exits.
+    Never,
+}
+
+/// Windows process priority class to launch the wrapped command with.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum ProcessPriority {
+    Realtime,
+    High,
+    AboveNormal,
+    Normal,
+    BelowNormal,
+    Idle,
+}
+
+impl ProcessPriority {
+    /// The `*_PRIORITY_CLASS` creation flag to OR into the child's creation flags.
+    fn creation_flag(self) -> u32 {
+        match self {
+            ProcessPriority::Realtime => REALTIME_PRIORITY_CLASS,
+            ProcessPriority::High => HIGH_PRIORITY_CLASS,
+            ProcessPriority::AboveNormal => ABOVE_NORMAL_PRIORITY_CLASS,
+            ProcessPriority::Normal => NORMAL_PRIORITY_CLASS,
+            ProcessPriority::BelowNormal => BELOW_NORMAL_PRIORITY_CLASS,
+            ProcessPriority::Idle => IDLE_PRIORITY_CLASS,
+        }
+    }
+}
+
+/// Everything `run_service` needs to supervise the wrapped process. Written to a sidecar
+/// JSON file next to the exe at install time, and read back by `service_main` on every
+/// service start, so behavior can be tweaked by editing the file instead of reinstalling.
+#[derive(Serialize, Deserialize, Clone)]
+struct ServiceConfig {
+    bat: String,
+    restart: RestartPolicy,
+    restart_delay: u64,
+    max_restarts: u32,
+    log_output: Option<PathBuf>,
+    stop_timeout: u64,
+    priority: ProcessPriority,
+    mode: InstallMode,
+}
+
+impl ServiceConfig {
+    fn from_cli(cli: &Cli, mode: InstallMode) -> Result<Self, Box<dyn std::error::Error>> {
+        let bat = cli.bat.clone().ok_or("--bat is required when installing the service")?;
+        Ok(Self {
+            bat,
+            restart: cli.restart,
+            restart_delay: cli.restart_delay,
+            max_restarts: cli.max_restarts,
+            log_output: cli.log_output.clone(),
+            stop_timeout: cli.stop_timeout,
+            priority: cli.priority,
+            mode,
+        })
+    }
+
+    /// Path of the sidecar config file for `service_name`, stored next to the running exe.
+    fn path_for(service_name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let exe_dir = std::env::current_exe()?
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf();
+        Ok(exe_dir.join(format!("{service_name}.config.json")))
+    }
+
+    fn save(&self, service_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Self::path_for(service_name)?;
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        info!("Wrote service config to {:?}", path);
+        Ok(())
+    }
+
+    fn load(service_name: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Self::path_for(service_name)?;
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read service config at {:?}: {}", path, e))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
 
-/// This function will store the `cli` object in the global state.
-fn store_cli_object(cli: Cli) {
-    let mut cli_lock = CLI.lock().unwrap();
-    *cli_lock = Some(cli);
+    fn delete(service_name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Self::path_for(service_name)?;
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
 }
 
 /// Initializes logging and writes logs in the same folder as the `.exe`
@@ -53,8 +173,26 @@ struct Cli {
     #[arg(long, value_hint = ValueHint::Other, help = "Example: MyService")]
     name: String,
 
-    #[arg(long, value_hint = ValueHint::FilePath, help = "Example: C:/scripts/run.bat")]
-    bat: String,
+    #[arg(long, value_hint = ValueHint::FilePath, help = "Example: C:/scripts/run.bat (required for `install`)")]
+    bat: Option<String>,
+
+    #[arg(long, value_enum, default_value = "on-failure", help = "Whether to restart the wrapped process when it exits")]
+    restart: RestartPolicy,
+
+    #[arg(long, default_value_t = 1, help = "Seconds to wait before the first restart attempt")]
+    restart_delay: u64,
+
+    #[arg(long, default_value_t = 5, help = "Give up restarting after this many restarts within the rolling window")]
+    max_restarts: u32,
+
+    #[arg(long, value_hint = ValueHint::DirPath, help = "Optional directory to tee raw child stdout/stderr into rotating log files")]
+    log_output: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 10, help = "Seconds to wait for the child to exit gracefully on stop before killing it")]
+    stop_timeout: u64,
+
+    #[arg(long, value_enum, default_value = "normal", help = "Windows process priority class for the wrapped command")]
+    priority: ProcessPriority,
 
     #[command(subcommand)]
     command: Option<Commands>,
@@ -62,20 +200,72 @@ struct Cli {
 
 #[derive(Subcommand, Clone)]
 enum Commands {
-    Install,
+    Install {
+        #[arg(long, value_enum, default_value = "service", help = "Install as a real Windows service (requires admin) or a per-user HKCU autostart entry")]
+        mode: InstallMode,
+
+        #[arg(long, help = "Defaults to the service name if omitted")]
+        display_name: Option<String>,
+
+        #[arg(long, help = "Set via a follow-up description API call after creation (service mode only)")]
+        description: Option<String>,
+
+        #[arg(long, value_enum, default_value = "manual", help = "How the service should start (service mode only)")]
+        start_type: StartType,
+
+        #[arg(long, help = "Account to run the service as; defaults to LocalSystem (service mode only)")]
+        account: Option<String>,
+
+        #[arg(long = "password-file", value_hint = ValueHint::FilePath, help = "Path to a file containing the password for --account, to avoid passing it on the command line (service mode only)")]
+        password_file: Option<PathBuf>,
+
+        #[arg(long = "depends-on", help = "Name of a service that must start before this one; can be repeated (service mode only)")]
+        depends_on: Vec<String>,
+    },
     Uninstall,
 }
 
+/// Which backend `install` registers the wrapper with.
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum InstallMode {
+    /// A real Windows service, managed by the SCM. Requires administrator rights.
+    Service,
+    /// A per-user autostart entry under HKCU\...\Run. No admin rights required.
+    User,
+}
+
+/// Service start type, as exposed on the `install` subcommand.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum StartType {
+    Auto,
+    Manual,
+    Disabled,
+}
+
+impl From<StartType> for ServiceStartType {
+    fn from(start_type: StartType) -> Self {
+        match start_type {
+            StartType::Auto => ServiceStartType::AutoStart,
+            StartType::Manual => ServiceStartType::OnDemand,
+            StartType::Disabled => ServiceStartType::Disabled,
+        }
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     setup_logging(); // ✅ Initialize logging
 
     let cli = Cli::parse();
-    info!("Received CLI arguments: name='{}', bat='{}'", cli.name, cli.bat);
+    info!("Received CLI arguments: name='{}', bat='{:?}'", cli.name, cli.bat);
 
     match &cli.command {
-        Some(Commands::Install) => {
-            info!("Installing service '{}'", cli.name);
-            install_service(&cli.name, &cli.bat)?;
+        Some(Commands::Install { mode, .. }) => {
+            info!("Installing service '{}' (mode={:?})", cli.name, mode);
+            match mode {
+                InstallMode::Service => install_service(&cli)?,
+                InstallMode::User => install_user_autostart(&cli)?,
+            }
             info!("Service '{}' installed successfully.", cli.name);
         }
         Some(Commands::Uninstall) => {
@@ -85,49 +275,112 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         None => {
             info!("Starting service '{}'", cli.name);
-            store_cli_object(cli.clone());
-            service_dispatcher::start(&cli.name, service_main)?;
+            let config = ServiceConfig::load(&cli.name)?;
+            match config.mode {
+                InstallMode::Service => service_dispatcher::start(&cli.name, service_main)?,
+                InstallMode::User => run_user_mode(&cli.name, &config)?,
+            }
         }
     }
     Ok(())
 }
 
-fn install_service(service_name: &str, bat_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+/// Reads the account password out of `--password-file` rather than taking it as a CLI arg,
+/// so it doesn't end up visible in process listings (tasklist/Process Explorer) or shell
+/// history for the whole `install` invocation. A single trailing newline, as left by most
+/// editors and `echo`, is stripped; everything else in the file is taken verbatim.
+fn read_password_file(path: Option<&Path>) -> Result<Option<OsString>, Box<dyn std::error::Error>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let mut contents = fs::read_to_string(path)?;
+    if contents.ends_with('\n') {
+        contents.pop();
+        if contents.ends_with('\r') {
+            contents.pop();
+        }
+    }
+    Ok(Some(OsString::from(contents)))
+}
+
+fn install_service(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(Commands::Install { display_name, description, start_type, account, password_file, depends_on, .. }) = &cli.command else {
+        return Err("install_service called without an Install command".into());
+    };
+
+    let service_name = &cli.name;
     let current_exe = std::env::current_exe()?;
     info!("Installing service '{}', using exe path: {:?}", service_name, current_exe);
 
+    let config = ServiceConfig::from_cli(cli, InstallMode::Service)?;
+    let account_password = read_password_file(password_file.as_deref())?;
+
     let service_info = ServiceInfo {
-        name: service_name.into(),
-        display_name: service_name.into(),
+        name: service_name.as_str().into(),
+        display_name: display_name.clone().unwrap_or_else(|| service_name.clone()).into(),
         service_type: ServiceType::OWN_PROCESS,
-        start_type: ServiceStartType::OnDemand,
+        start_type: (*start_type).into(),
         error_control: ServiceErrorControl::Normal,
         executable_path: current_exe.clone(),
-        launch_arguments: vec![
-            "--name".into(),
-            service_name.into(),
-            "--bat".into(),
-            bat_path.into(),
-        ],
-        dependencies: vec![],
-        account_name: None,
-        account_password: None,
+        launch_arguments: vec!["--name".into(), service_name.as_str().into()],
+        dependencies: depends_on.iter().cloned().map(ServiceDependency::Service).collect(),
+        account_name: account.as_deref().map(OsString::from),
+        account_password,
     };
 
     let service_manager = ServiceManager::local_computer(
         None::<&str>,
         ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE
     )?;
-    let _service = service_manager.create_service(&service_info, ServiceAccess::empty())?;
+    let service = service_manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
+    config.save(service_name)?;
+    if let Some(description) = description {
+        service.set_description(description)?;
+    }
     info!("Service '{}' installed successfully.", service_name);
     Ok(())
 }
 
+/// Registers the wrapper exe to autostart at logon via the current user's `Run` key,
+/// instead of creating a real Windows service. Requires no administrator rights, since it
+/// writes only to HKEY_CURRENT_USER; the wrapper runs the supervise loop as a normal
+/// foreground process started by the logon autostart mechanism (see `run_user_mode`).
+fn install_user_autostart(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let service_name = &cli.name;
+    let current_exe = std::env::current_exe()?;
+    info!("Registering '{}' for user-mode autostart, using exe path: {:?}", service_name, current_exe);
+
+    let config = ServiceConfig::from_cli(cli, InstallMode::User)?;
+
+    let run_key = RegKey::predef(HKEY_CURRENT_USER)
+        .create_subkey(RUN_KEY_PATH)?
+        .0;
+    let command = format!("\"{}\" --name \"{}\"", current_exe.display(), service_name);
+    run_key.set_value(service_name, &command)?;
+    config.save(service_name)?;
+
+    info!("Registered '{}' to autostart at logon via HKCU\\{}.", service_name, RUN_KEY_PATH);
+    Ok(())
+}
+
 fn uninstall_service(service_name: &str) -> Result<(), Box<dyn std::error::Error>> {
     info!("Uninstalling service '{}'", service_name);
-    let service_manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
-    let service = service_manager.open_service(service_name, ServiceAccess::DELETE)?;
-    service.delete()?;
+    let mode = ServiceConfig::load(service_name).map(|c| c.mode).unwrap_or(InstallMode::Service);
+
+    match mode {
+        InstallMode::Service => {
+            let service_manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+            let service = service_manager.open_service(service_name, ServiceAccess::DELETE)?;
+            service.delete()?;
+        }
+        InstallMode::User => {
+            if let Ok(run_key) = RegKey::predef(HKEY_CURRENT_USER).open_subkey_with_flags(RUN_KEY_PATH, KEY_SET_VALUE) {
+                let _ = run_key.delete_value(service_name);
+            }
+        }
+    }
+
+    ServiceConfig::delete(service_name)?;
     info!("Service '{}' uninstalled successfully.", service_name);
     Ok(())
 }
@@ -135,18 +388,22 @@ fn uninstall_service(service_name: &str) -> Result<(), Box<dyn std::error::Error
 extern "system" fn service_main(argc: u32, argv: *mut *mut u16) {
     debug!("Starting service_main");
 
-    let cli = {
-        let cli_lock = CLI.lock().unwrap();
-        cli_lock.as_ref().cloned()
+    // The SCM passes the service name as the first service argument; this is how
+    // `service_main` learns which service it's running as without any shared global state.
+    let args = raw_args_to_vec(argc, argv);
+    let Some(service_name) = args.first() else {
+        error!("service_main was started without a service name argument");
+        return;
     };
 
-    if let Some(cli) = cli {
-        info!("Executing service_main for '{}'", cli.name);
-        if let Err(e) = run_service(&cli.name, &cli.bat) {
-            error!("Service error: {}", e);
+    match ServiceConfig::load(service_name) {
+        Ok(config) => {
+            info!("Executing service_main for '{}'", service_name);
+            if let Err(e) = run_service(service_name, &config) {
+                error!("Service error: {}", e);
+            }
         }
-    } else {
-        error!("Failed to retrieve CLI arguments in service_main");
+        Err(e) => error!("Failed to load service config for '{}': {}", service_name, e),
     }
 }
 
@@ -174,9 +431,244 @@ fn raw_args_to_vec(argc: u32, argv: *mut *mut u16) -> Vec<String> {
 }
 
 
-/// Runs the service by starting the batch process in a child process. It then polls
-/// for either a stop signal from the service control or the natural termination of the child.
-fn run_service(service_name: &str, bat_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+fn spawn_child(bat_path: &str, priority: ProcessPriority) -> std::io::Result<Child> {
+    Command::new("cmd.exe")
+        .args(&["/C", bat_path])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Puts the child in its own process group so we can target it (and not ourselves)
+        // with CTRL_BREAK_EVENT during a graceful stop. CREATE_NO_WINDOW keeps cmd.exe from
+        // popping a visible console, since `ensure_console` below has already given this
+        // process the hidden console it inherits a handle from.
+        .creation_flags(CREATE_NEW_PROCESS_GROUP | CREATE_NO_WINDOW | priority.creation_flag())
+        .spawn()
+}
+
+/// Sends `CTRL_BREAK_EVENT` to the child's process group. Returns `false` if the signal
+/// could not be delivered, in which case the caller should fall back to a hard kill.
+fn send_ctrl_break(child: &Child) -> bool {
+    unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, child.id()) != 0 }
+}
+
+/// Services launched by the SCM run in Session 0 with no desktop and no console, so
+/// `GenerateConsoleCtrlEvent` in `send_ctrl_break` has nothing to signal from and silently
+/// fails every time, degrading every graceful stop to a hard `child.kill()`. Allocating a
+/// console here (before any child is spawned) gives this process one to send from; it's
+/// never shown since nothing attaches a window to it and `spawn_child` passes
+/// `CREATE_NO_WINDOW` to the child. A no-op (and harmless) if a console already exists.
+/// Only `run_service` calls this; `run_user_mode` does not.
+fn ensure_console() {
+    if unsafe { AllocConsole() } == 0 {
+        debug!("AllocConsole did not allocate a new console (likely one already exists)");
+    }
+}
+
+/// Where to report `ServiceStatus` updates. Real services report to the SCM; a user-mode
+/// autostart process (see `run_user_mode`) has no SCM to report to and just no-ops.
+enum Reporter {
+    Scm(ServiceStatusHandle),
+    Standalone,
+}
+
+impl Reporter {
+    fn set_status(&self, status: ServiceStatus) -> Result<(), Box<dyn std::error::Error>> {
+        if let Reporter::Scm(handle) = self {
+            handle.set_service_status(status)?;
+        }
+        Ok(())
+    }
+}
+
+/// Attempts a graceful shutdown of `child`: sends CTRL_BREAK_EVENT, then polls for exit
+/// up to `timeout`, reporting `StopPending` with an increasing checkpoint so the SCM
+/// doesn't consider the service hung. Falls back to `child.kill()` if the child ignores
+/// the signal or it could not be delivered.
+fn graceful_stop(
+    child: &mut Child,
+    reporter: &Reporter,
+    timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !send_ctrl_break(child) {
+        warn!("Failed to deliver CTRL_BREAK_EVENT to child; killing it instead.");
+        let _ = child.kill();
+        let _ = child.wait();
+        return Ok(());
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut checkpoint: u32 = 1;
+    loop {
+        if let Ok(Some(status)) = child.try_wait() {
+            info!("Child exited gracefully after stop signal: {}", status);
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        reporter.set_status(ServiceStatusEx::stop_pending(checkpoint, Duration::from_secs(2)))?;
+        checkpoint += 1;
+        thread::sleep(Duration::from_millis(500));
+    }
+
+    warn!("Child did not exit within --stop-timeout; killing it.");
+    let _ = child.kill();
+    let _ = child.wait();
+    Ok(())
+}
+
+/// A raw-output log file for the wrapped child process that rotates by size and by age,
+/// so a long-lived, noisy process doesn't grow one unbounded file.
+struct RollingFile {
+    dir: PathBuf,
+    prefix: String,
+    file: fs::File,
+    opened_at: Instant,
+    bytes_written: u64,
+}
+
+impl RollingFile {
+    fn new(dir: PathBuf, prefix: String) -> std::io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let file = Self::open_segment(&dir, &prefix)?;
+        Ok(Self { dir, prefix, file, opened_at: Instant::now(), bytes_written: 0 })
+    }
+
+    fn open_segment(dir: &Path, prefix: &str) -> std::io::Result<fs::File> {
+        let stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let path = dir.join(format!("{prefix}.{stamp}.log"));
+        fs::OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.bytes_written >= CHILD_LOG_MAX_BYTES || self.opened_at.elapsed() >= CHILD_LOG_MAX_AGE {
+            match Self::open_segment(&self.dir, &self.prefix) {
+                Ok(file) => {
+                    self.file = file;
+                    self.opened_at = Instant::now();
+                    self.bytes_written = 0;
+                }
+                Err(e) => error!("Failed to roll child-output log file: {}", e),
+            }
+        }
+        if let Err(e) = writeln!(self.file, "{line}") {
+            error!("Failed to write child-output log line: {}", e);
+            return;
+        }
+        self.bytes_written += line.len() as u64 + 1;
+    }
+}
+
+/// Spawns reader threads that pump the child's stdout/stderr lines into the service's
+/// `tracing` subscriber (stdout at `info`, stderr at `warn`), optionally teeing the raw
+/// lines into `sink` as well. Returns the join handles so callers can wait for both
+/// streams to close once the child exits.
+fn spawn_output_pumps(
+    child: &mut Child,
+    sink: Option<Arc<Mutex<RollingFile>>>,
+) -> (thread::JoinHandle<()>, thread::JoinHandle<()>) {
+    let stdout = child.stdout.take().expect("child stdout was piped");
+    let stderr = child.stderr.take().expect("child stderr was piped");
+
+    let stdout_sink = sink.clone();
+    let stdout_handle = thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            info!(target: "child", "{}", line);
+            if let Some(sink) = &stdout_sink {
+                sink.lock().unwrap().write_line(&line);
+            }
+        }
+    });
+
+    let stderr_sink = sink;
+    let stderr_handle = thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            warn!(target: "child", "{}", line);
+            if let Some(sink) = &stderr_sink {
+                sink.lock().unwrap().write_line(&line);
+            }
+        }
+    });
+
+    (stdout_handle, stderr_handle)
+}
+
+/// Outcome of waiting on a single run of the child process.
+enum WaitOutcome {
+    /// A stop was requested (SCM Stop control, or Ctrl-C in user mode); the child is still
+    /// running and has not been signalled yet.
+    StopRequested,
+    /// The child exited on its own.
+    Exited(ExitStatus),
+}
+
+/// Constructors for the `ServiceStatus` values reported to the SCM, so the fields that are
+/// the same on every call aren't repeated at each `set_service_status` call site.
+struct ServiceStatusEx;
+
+impl ServiceStatusEx {
+    fn start_pending(checkpoint: u32, wait_hint: Duration) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::StartPending,
+            controls_accepted: ServiceControlAccept::STOP,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint,
+            wait_hint,
+            process_id: Some(std::process::id()),
+        }
+    }
+
+    fn running() -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::Running,
+            controls_accepted: ServiceControlAccept::STOP,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::from_secs(5),
+            process_id: Some(std::process::id()),
+        }
+    }
+
+    fn stop_pending(checkpoint: u32, wait_hint: Duration) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::StopPending,
+            controls_accepted: ServiceControlAccept::empty(),
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint,
+            wait_hint,
+            process_id: Some(std::process::id()),
+        }
+    }
+
+    fn stopped(exit_code: ServiceExitCode) -> ServiceStatus {
+        ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::Stopped,
+            controls_accepted: ServiceControlAccept::empty(),
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::from_secs(5),
+            process_id: None,
+        }
+    }
+}
+
+/// Converts a child's natural exit status into the `ServiceExitCode` reported to the SCM,
+/// so monitoring tools and recovery actions can see that the wrapped program crashed.
+fn exit_code_for(status: Option<ExitStatus>) -> ServiceExitCode {
+    match status {
+        Some(status) if !status.success() => ServiceExitCode::ServiceSpecific(status.code().unwrap_or(1) as u32),
+        _ => ServiceExitCode::Win32(0),
+    }
+}
+
+/// Runs as a real Windows service: registers a control handler with the SCM and reports
+/// status through it while supervising the child.
+fn run_service(service_name: &str, config: &ServiceConfig) -> Result<(), Box<dyn std::error::Error>> {
+    ensure_console();
+
     let (control_tx, control_rx) = channel();
 
     let event_handler = move |control_event| -> ServiceControlHandlerResult {
@@ -188,76 +680,147 @@ fn run_service(service_name: &str, bat_path: &str) -> Result<(), Box<dyn std::er
     };
 
     let status_handle = service_control_handler::register(service_name, event_handler)?;
-
     info!(
         "Service '{}' registered. Starting batch process...",
         service_name
     );
-    status_handle.set_service_status(ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::StartPending,
-        controls_accepted: ServiceControlAccept::STOP,
-        exit_code: Default::default(),
-        checkpoint: 1,
-        wait_hint: Duration::from_secs(10),
-        process_id: Some(std::process::id()),
+    run_supervised(service_name, config, &control_rx, &Reporter::Scm(status_handle))
+}
+
+/// Runs as a plain foreground process under a user-mode (HKCU `Run` key) autostart: there
+/// is no SCM to register with, so Ctrl-C is trapped directly to request a stop.
+fn run_user_mode(service_name: &str, config: &ServiceConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let (control_tx, control_rx) = channel();
+    ctrlc::set_handler(move || {
+        let _ = control_tx.send("stop");
     })?;
+    info!("Running '{}' in user mode; starting batch process...", service_name);
+    run_supervised(service_name, config, &control_rx, &Reporter::Standalone)
+}
 
-    let mut child = Command::new("cmd.exe")
-        .args(&["/K", bat_path])
-        .stdout(Stdio::inherit())
-        .stderr(Stdio::inherit())
-        .spawn()?;
+/// Supervises the wrapped batch process: spawns it, pumps its output, waits for either a
+/// stop request or a natural exit, and restarts it per `config.restart` until a stop is
+/// requested or `--max-restarts` is exhausted. Shared by both the SCM-managed service path
+/// and the user-mode autostart path; `reporter` is a no-op in the latter.
+fn run_supervised(
+    service_name: &str,
+    config: &ServiceConfig,
+    control_rx: &Receiver<&str>,
+    reporter: &Reporter,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bat_path = &config.bat;
+
+    reporter.set_status(ServiceStatusEx::start_pending(1, Duration::from_secs(10)))?;
+
+    let output_sink = match &config.log_output {
+        Some(dir) => Some(Arc::new(Mutex::new(RollingFile::new(dir.clone(), "child-output".into())?))),
+        None => None,
+    };
 
-    info!("Batch file '{}' started successfully.", bat_path);
+    let mut restart_count: u32 = 0;
+    let mut restart_delay = Duration::from_secs(config.restart_delay.max(1));
+    let mut last_exit_status: Option<ExitStatus> = None;
 
-    status_handle.set_service_status(ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::Running,
-        controls_accepted: ServiceControlAccept::STOP,
-        exit_code: Default::default(),
-        checkpoint: 0,
-        wait_hint: Duration::from_secs(5),
-        process_id: Some(std::process::id()),
-    })?;
+    loop {
+        let mut child = spawn_child(bat_path, config.priority)?;
+        info!("Batch file '{}' started successfully.", bat_path);
+        let (stdout_pump, stderr_pump) = spawn_output_pumps(&mut child, output_sink.clone());
+
+        reporter.set_status(ServiceStatusEx::running())?;
+
+        let started_at = Instant::now();
+        let outcome = wait_for_stop_signal(control_rx, &mut child);
+
+        let exit_status = match outcome {
+            WaitOutcome::StopRequested => {
+                info!("Stop signal received; attempting graceful shutdown...");
+                graceful_stop(&mut child, reporter, Duration::from_secs(config.stop_timeout))?;
+                let _ = stdout_pump.join();
+                let _ = stderr_pump.join();
+                break;
+            }
+            WaitOutcome::Exited(status) => {
+                info!("Child process finished naturally with status: {}", status);
+                let _ = stdout_pump.join();
+                let _ = stderr_pump.join();
+                status
+            }
+        };
+
+        if started_at.elapsed() >= RESTART_WINDOW {
+            debug!("Child stayed up past the restart window; resetting restart counter.");
+            restart_count = 0;
+            restart_delay = Duration::from_secs(config.restart_delay.max(1));
+        }
 
-    let stop_requested = wait_for_stop_signal(&control_rx, &mut child);
+        let should_restart = match config.restart {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => !exit_status.success(),
+            RestartPolicy::Never => false,
+        };
+
+        if !should_restart {
+            info!("Restart policy '{:?}' does not call for a restart; stopping.", config.restart);
+            last_exit_status = Some(exit_status);
+            break;
+        }
+        if restart_count >= config.max_restarts {
+            warn!(
+                "Child has been restarted {} times without staying up for {:?}; --max-restarts={} reached, giving up.",
+                restart_count, RESTART_WINDOW, config.max_restarts
+            );
+            last_exit_status = Some(exit_status);
+            break;
+        }
 
-    if stop_requested {
-        info!("Stop signal received; child process was terminated.");
-    } else {
-        info!("Child process finished naturally.");
+        restart_count += 1;
+        warn!(
+            "Restarting child (attempt {}/{}) in {:?}...",
+            restart_count, config.max_restarts, restart_delay
+        );
+        if sleep_or_stop(control_rx, restart_delay) {
+            info!("Stop signal received during restart backoff; shutting down without restarting.");
+            break;
+        }
+        restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
     }
 
     info!("Service '{}' is stopping...", service_name);
-    status_handle.set_service_status(ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::Stopped,
-        controls_accepted: ServiceControlAccept::empty(),
-        exit_code: Default::default(),
-        checkpoint: 0,
-        wait_hint: Duration::from_secs(5),
-        process_id: None,
-    })?;
+    reporter.set_status(ServiceStatusEx::stopped(exit_code_for(last_exit_status)))?;
     info!("Service '{}' has stopped.", service_name);
     Ok(())
 }
 
+/// Waits out `duration` in short ticks instead of a single blocking sleep, so a stop
+/// request arriving during crash-restart backoff (up to `MAX_RESTART_DELAY`) is noticed
+/// right away rather than sitting unread until the backoff finishes. Returns `true` if a
+/// stop signal was seen, `false` if `duration` simply elapsed.
+fn sleep_or_stop(control_rx: &Receiver<&str>, duration: Duration) -> bool {
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+    let deadline = Instant::now() + duration;
+    loop {
+        if let Ok("stop") = control_rx.try_recv() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+    }
+}
+
 /// Polls for either a stop signal from the service control or for the child process
 /// to finish naturally. No additional thread is spawned here.
-fn wait_for_stop_signal(control_rx: &Receiver<&str>, child: &mut Child) -> bool {
+fn wait_for_stop_signal(control_rx: &Receiver<&str>, child: &mut Child) -> WaitOutcome {
     loop {
-        // If a stop signal is received, kill the child process.
+        // If a stop signal is received, hand control back so the caller can attempt a
+        // graceful shutdown before resorting to a hard kill.
         if let Ok("stop") = control_rx.try_recv() {
-            info!("Stop signal received from service control; terminating child process...");
-            let _ = child.kill();
-            let _ = child.wait();
-            return true;
+            return WaitOutcome::StopRequested;
         }
         // If the child process has finished naturally, return immediately.
-        if let Ok(Some(_)) = child.try_wait() {
-            info!("Child process finished naturally.");
-            return false;
+        if let Ok(Some(status)) = child.try_wait() {
+            return WaitOutcome::Exited(status);
         }
         info!("Service running...");
         thread::sleep(Duration::from_secs(1));